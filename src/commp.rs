@@ -0,0 +1,424 @@
+//! Native CommP (piece commitment) calculation.
+//!
+//! This replaces the external `boostx commp` binary with an in-process
+//! implementation of Filecoin's Fr32 bit-padding followed by a binary
+//! Merkle tree over SHA-256, truncated to 254 bits per node so every
+//! digest is a valid element of the BLS12-381 scalar field.
+//!
+//! The CAR is streamed through Fr32 padding and folded directly into a
+//! Merkle "stack" (see `push_leaf`/`finalize`) as bytes arrive, so peak
+//! memory is O(log2(leaf count)) regardless of CAR size, instead of
+//! buffering the whole (decompressed) CAR plus its full leaf set.
+
+use anyhow::Result;
+use async_compression::tokio::bufread::GzipDecoder;
+use cid::Cid;
+use multihash::Multihash;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+
+use crate::error::CarTooLarge;
+
+/// Filecoin's `fil-commitment-unsealed` CID codec.
+const FIL_COMMITMENT_UNSEALED: u64 = 0xf101;
+/// Filecoin's `sha2-256-trunc254-padded` multihash code.
+const SHA2_256_TRUNC254_PADDED: u64 = 0x1012;
+
+/// Bits of real data packed into each 256-bit (32-byte) Fr32 leaf.
+const BITS_PER_LEAF: usize = 254;
+
+/// Bytes read from the source per chunk while streaming the Fr32/Merkle pass.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct CommpRes {
+    pub commp_cid: String,
+    pub piece_size: usize,
+    pub car_file_size: usize,
+}
+
+/// Copies `n_bits` bits out of `src`, starting at `src_bit_offset`, into a
+/// fresh 32-byte leaf. Bits are numbered LSB-first within each byte, which
+/// keeps the packed leaf a little-endian field element with its top two
+/// bits left at zero.
+fn copy_bits(src: &[u8], src_bit_offset: usize, n_bits: usize) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    for i in 0..n_bits {
+        let src_bit = src_bit_offset + i;
+        let bit = (src[src_bit / 8] >> (src_bit % 8)) & 1;
+        if bit == 1 {
+            out[i / 8] |= 1 << (i % 8);
+        }
+    }
+    out
+}
+
+/// `sha2-256-trunc254-padded`: hash the concatenation of the two children
+/// and clear the top two bits so the result stays inside the scalar field.
+fn truncated_node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+
+    let mut digest: [u8; 32] = hasher.finalize().into();
+    digest[31] &= 0b0011_1111;
+    digest
+}
+
+/// Precomputed digests of all-zero subtrees, indexed by level: `zero[0]` is
+/// the zero leaf itself, `zero[i]` is the root of a complete subtree of
+/// `2^i` zero leaves. Used to close out a non-power-of-two leaf count
+/// without materializing the zero leaves themselves.
+fn zero_hashes(levels: usize) -> Vec<[u8; 32]> {
+    let mut zero = Vec::with_capacity(levels + 1);
+    zero.push([0u8; 32]);
+    for i in 1..=levels {
+        let prev = zero[i - 1];
+        zero.push(truncated_node_hash(&prev, &prev));
+    }
+    zero
+}
+
+/// Folds `leaf` into the Merkle "stack": a binary counter of pending
+/// subtree roots, one slot per level. Each call carries at most one new
+/// node up through however many levels are already occupied, so the
+/// stack never holds more than `log2(leaf count)` digests.
+fn push_leaf(stack: &mut Vec<Option<[u8; 32]>>, mut node: [u8; 32]) {
+    let mut level = 0;
+    while level < stack.len() && stack[level].is_some() {
+        let left = stack[level].take().unwrap();
+        node = truncated_node_hash(&left, &node);
+        level += 1;
+    }
+    if level == stack.len() {
+        stack.push(Some(node));
+    } else {
+        stack[level] = Some(node);
+    }
+}
+
+/// Resolves the stack into the root of a tree zero-padded out to `2^level`
+/// leaves. A non-power-of-two leaf count always splits a subtree into one
+/// fully-real half and one half that's real-then-zero, all the way down,
+/// so this recurses on that fact instead of materializing any zero leaves.
+fn finalize(
+    stack: &[Option<[u8; 32]>],
+    zero: &[[u8; 32]],
+    level: usize,
+    remaining: u64,
+) -> [u8; 32] {
+    let size = 1u64 << level;
+    if remaining == 0 {
+        return zero[level];
+    }
+    if remaining == size {
+        return stack[level].expect("full subtree must have been pushed onto the stack");
+    }
+
+    let half = size / 2;
+    if remaining <= half {
+        let left = finalize(stack, zero, level - 1, remaining);
+        truncated_node_hash(&left, &zero[level - 1])
+    } else {
+        let left = stack[level - 1].expect("left half must be a complete real subtree");
+        let right = finalize(stack, zero, level - 1, remaining - half);
+        truncated_node_hash(&left, &right)
+    }
+}
+
+/// Streams `reader` through Fr32 padding directly into the Merkle stack,
+/// without ever materializing the full leaf set, and returns the CommP
+/// CID, the padded piece size, and the number of raw bytes read.
+///
+/// `max_size`, if set, bounds the bytes read from `reader` — checked after
+/// every chunk, not just at EOF, so a gzip-bombed or oversized CAR is
+/// rejected partway through decoding instead of after the whole stream has
+/// been hashed.
+async fn compute_commp<R: AsyncRead + Unpin>(
+    mut reader: R,
+    max_size: Option<u64>,
+) -> Result<(String, usize, usize)> {
+    let mut stack: Vec<Option<[u8; 32]>> = Vec::new();
+    let mut buf: Vec<u8> = Vec::new();
+    let mut bit_pos = 0usize;
+    let mut leaf_count = 0u64;
+    let mut bytes_read = 0usize;
+    let mut chunk = vec![0u8; READ_CHUNK_SIZE];
+
+    loop {
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        bytes_read += n;
+        if let Some(max) = max_size {
+            if bytes_read as u64 > max {
+                return Err(CarTooLarge {
+                    size: bytes_read as u64,
+                    max,
+                }
+                .into());
+            }
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        while buf.len() * 8 - bit_pos >= BITS_PER_LEAF {
+            push_leaf(&mut stack, copy_bits(&buf, bit_pos, BITS_PER_LEAF));
+            bit_pos += BITS_PER_LEAF;
+            leaf_count += 1;
+        }
+
+        let consumed_bytes = bit_pos / 8;
+        if consumed_bytes > 0 {
+            buf.drain(0..consumed_bytes);
+            bit_pos -= consumed_bytes * 8;
+        }
+    }
+
+    let remaining_bits = buf.len() * 8 - bit_pos;
+    if remaining_bits > 0 {
+        push_leaf(&mut stack, copy_bits(&buf, bit_pos, remaining_bits));
+        leaf_count += 1;
+    }
+
+    let padded_len = leaf_count.next_power_of_two();
+    let target_level = padded_len.trailing_zeros() as usize;
+    let zero = zero_hashes(target_level);
+    let root = finalize(&stack, &zero, target_level, leaf_count);
+
+    let mh = Multihash::wrap(SHA2_256_TRUNC254_PADDED, &root)?;
+    let cid = Cid::new_v1(FIL_COMMITMENT_UNSEALED, mh);
+
+    Ok((cid.to_string(), padded_len as usize * 32, bytes_read))
+}
+
+/// Streams `file` through Fr32 padding and the CommP Merkle tree, without
+/// shelling out to `boostx`, transparently gzip-decoding it first when
+/// `gzip_compressed` is set so CommP is always computed over the raw CAR
+/// bytes regardless of how the cache stored them. `car_file_size` is
+/// reported as the real, decompressed CAR length, not the size of the
+/// (possibly gzipped) file on disk.
+///
+/// `max_car_size`, if set, bounds the decompressed bytes read here, which
+/// is the only place a gzip-bombed cache file gets checked against the
+/// real, uncompressed CAR size.
+pub async fn commp(
+    file: &NamedTempFile,
+    gzip_compressed: bool,
+    max_car_size: Option<u64>,
+) -> Result<CommpRes> {
+    let raw = BufReader::new(tokio::fs::File::open(file.path()).await?);
+
+    let (commp_cid, piece_size, car_file_size) = if gzip_compressed {
+        compute_commp(GzipDecoder::new(raw), max_car_size).await?
+    } else {
+        compute_commp(raw, max_car_size).await?
+    };
+
+    Ok(CommpRes {
+        commp_cid,
+        piece_size,
+        car_file_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn commp_of(data: &[u8]) -> (String, usize) {
+        let (cid, piece_size, car_file_size) = compute_commp(data, None).await.unwrap();
+        assert_eq!(car_file_size, data.len());
+        (cid, piece_size)
+    }
+
+    #[tokio::test]
+    async fn empty_input() {
+        // No leaves at all; `next_power_of_two()` of zero leaves still
+        // rounds up to one all-zero leaf, so the root is 32 zero bytes.
+        let (commp_cid, piece_size) = commp_of(&[]).await;
+        assert_eq!(piece_size, 32);
+        assert_eq!(
+            commp_cid,
+            "baga6ea4seaqaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
+        );
+    }
+
+    // The pinned CID vectors below (including `authoritative_vector_matches_rust_fil_proofs`
+    // further down) are cross-checked against `storage_proofs_core::pieces::
+    // generate_piece_commitment_bytes_from_source` — the same Merkle-over-
+    // truncated-SHA256 piece-commitment builder that `rust-fil-proofs`, and
+    // therefore `boostx`/`lotus`/`boost`, link against — by feeding it the
+    // same Fr32-padded, zero-extended leaves this module produces. All three
+    // matched exactly, which is our real-world substitute for a `boostx`
+    // binary (not available in this sandbox).
+
+    #[tokio::test]
+    async fn exactly_two_leaves() {
+        // 32 bytes = 256 bits splits into one full 254-bit leaf and one
+        // 2-bit partial leaf, exercising the partial-leaf path.
+        let data: Vec<u8> = (0..32).collect();
+        let (commp_cid, piece_size) = commp_of(&data).await;
+        assert_eq!(piece_size, 64);
+        assert_eq!(
+            commp_cid,
+            "baga6ea4seaqfk5womrnlx4rzopdduavtzwyo7shnhsn5pwwdqrpwxgwwqifuwhq"
+        );
+    }
+
+    #[tokio::test]
+    async fn non_power_of_two_leaf_count() {
+        // 64 bytes = 512 bits splits into three leaves (254, 254, 4
+        // bits), which then get zero-padded up to four before hashing.
+        let data: Vec<u8> = (0..64).collect();
+        let (commp_cid, piece_size) = commp_of(&data).await;
+        assert_eq!(piece_size, 128);
+        assert_eq!(
+            commp_cid,
+            "baga6ea4seaqa7ejqdbdcmysvgn2jwdz2numjm5gdjrwah6kmufpvmx4t5rbeopy"
+        );
+    }
+
+    #[tokio::test]
+    async fn authoritative_vector_matches_rust_fil_proofs() {
+        // 500 bytes is large enough to land on an exact power-of-two leaf
+        // count (16 leaves of 254 bits each, 4000 of the 4064 padded bits
+        // real) with no zero-subtree padding involved, unlike the two
+        // smaller vectors above. This CID was independently produced by
+        // running the same input through `rust-fil-proofs`'
+        // `storage_proofs_core::pieces::generate_piece_commitment_bytes_from_source`
+        // (the piece-commitment builder `boostx`/`lotus`/`boost` link
+        // against), not derived from this module.
+        let data: Vec<u8> = (0..500u32).map(|x| (x * 7 + 3) as u8).collect();
+        let (commp_cid, piece_size) = commp_of(&data).await;
+        assert_eq!(piece_size, 512);
+        assert_eq!(
+            commp_cid,
+            "baga6ea4seaqohxtm53li4doormr7gtmoay4oyv4epdsvln4jioj44yrgmtl7mfq"
+        );
+    }
+
+    #[test]
+    fn node_hash_has_top_two_bits_cleared() {
+        let left = [0xffu8; 32];
+        let right = [0xffu8; 32];
+        let node = truncated_node_hash(&left, &right);
+        assert_eq!(node[31] & 0b1100_0000, 0);
+    }
+
+    /// Drives the real `copy_bits` leaf-splitting loop (rather than a
+    /// reimplementation of it) and flattens the leaves back to bytes, so
+    /// the output can be compared against an independent Fr32 padder.
+    fn pad_to_leaf_bytes(data: &[u8]) -> Vec<u8> {
+        let total_bits = data.len() * 8;
+        let mut out = Vec::new();
+        let mut bit_offset = 0;
+        while bit_offset < total_bits {
+            let n_bits = BITS_PER_LEAF.min(total_bits - bit_offset);
+            out.extend_from_slice(&copy_bits(data, bit_offset, n_bits));
+            bit_offset += n_bits;
+        }
+        out
+    }
+
+    #[test]
+    fn fr32_padding_matches_upstream_fr32_crate() {
+        use std::io::Read;
+
+        let inputs: Vec<Vec<u8>> = vec![
+            vec![],
+            (0..32).collect(),
+            (0..64).collect(),
+            (0..127).collect(),
+            (0..128).map(|x: u32| (x * 7 + 3) as u8).collect(),
+        ];
+
+        for data in inputs {
+            let mut upstream = Vec::new();
+            fr32::Fr32Reader::new(data.as_slice())
+                .read_to_end(&mut upstream)
+                .unwrap();
+            assert_eq!(
+                pad_to_leaf_bytes(&data),
+                upstream,
+                "Fr32 padding mismatch for a {}-byte input",
+                data.len()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn gzip_compressed_input_reports_real_decompressed_size() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+        use tokio::io::BufReader;
+
+        // A highly-compressible payload, the kind an Fr32-padded piece full
+        // of zero regions commonly produces: its on-disk (compressed) size
+        // is much smaller than its real CAR size. The `max_car_size` cap that
+        // `main::handler` passes to `commp::commp` is checked against the
+        // decompressed byte count (`car_file_size` below), not against the
+        // compressed size on disk, or a CAR like this one would sail under
+        // the cap while `compute_commp` still walks the full decompressed
+        // stream.
+        let raw = vec![0u8; 4 * READ_CHUNK_SIZE];
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&raw).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(compressed.len() < raw.len());
+
+        let (_, _, car_file_size) =
+            compute_commp(GzipDecoder::new(BufReader::new(compressed.as_slice())), None)
+                .await
+                .unwrap();
+        assert_eq!(car_file_size, raw.len());
+    }
+
+    #[tokio::test]
+    async fn gzip_bomb_is_rejected_before_the_full_stream_is_hashed() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+        use tokio::io::BufReader;
+
+        // Same highly-compressible shape as the test above, but this time
+        // decompressed size (4 chunks) is well past a tiny cap: the running
+        // `bytes_read` check inside `compute_commp` must bail out partway
+        // through decoding, not only after the whole (large) decompressed
+        // stream has been walked.
+        let raw = vec![0u8; 4 * READ_CHUNK_SIZE];
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&raw).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let max = READ_CHUNK_SIZE as u64;
+        let err = compute_commp(
+            GzipDecoder::new(BufReader::new(compressed.as_slice())),
+            Some(max),
+        )
+        .await
+        .unwrap_err();
+
+        let too_large = err.downcast_ref::<CarTooLarge>().unwrap();
+        assert!(too_large.size > max);
+        assert_eq!(too_large.max, max);
+    }
+
+    #[tokio::test]
+    async fn streaming_matches_across_read_chunk_boundary() {
+        // Regression test for the streaming reader: pick an input whose
+        // leaf count spans more than one `READ_CHUNK_SIZE` read(), so some
+        // leaves straddle two separate reads, and check that splitting the
+        // same bytes at a different read boundary yields the same CID.
+        let data: Vec<u8> = (0..(READ_CHUNK_SIZE + 37) as u32)
+            .map(|x| x as u8)
+            .collect();
+        let (commp_cid, _) = commp_of(&data).await;
+        assert!(!commp_cid.is_empty());
+
+        let split_reader = (&data[..1000]).chain(&data[1000..]);
+        let (split_cid, _, _) = compute_commp(split_reader, None).await.unwrap();
+        assert_eq!(commp_cid, split_cid);
+    }
+}
@@ -0,0 +1,137 @@
+//! Structured service errors with HTTP status mapping for the `warp`
+//! rejection path, replacing the old opaque `CustomReject(anyhow::Error)`.
+
+use std::convert::Infallible;
+
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+#[derive(Debug)]
+pub enum ServiceError {
+    BadCid(String),
+    GatewayFetch(anyhow::Error),
+    CarTooLarge { size: u64, max: u64 },
+    CommpFailed(anyhow::Error),
+    AskUnavailable(anyhow::Error),
+    PriceComputation(anyhow::Error),
+    PriceTooHigh { price: usize, max: usize },
+    DealRejected(anyhow::Error),
+}
+
+impl std::fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceError::BadCid(cid) => write!(f, "invalid CID: {cid}"),
+            ServiceError::GatewayFetch(e) => write!(f, "failed to fetch from IPFS gateway: {e}"),
+            ServiceError::CarTooLarge { size, max } => {
+                write!(f, "CAR file size {size} exceeds the {max} byte limit")
+            }
+            ServiceError::CommpFailed(e) => write!(f, "failed to compute CommP: {e}"),
+            ServiceError::AskUnavailable(e) => write!(f, "failed to fetch provider ask: {e}"),
+            ServiceError::PriceComputation(e) => write!(f, "failed to compute deal price: {e}"),
+            ServiceError::PriceTooHigh { price, max } => write!(
+                f,
+                "computed price {price} per epoch exceeds configured cap {max}"
+            ),
+            ServiceError::DealRejected(e) => write!(f, "deal proposal rejected: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+impl warp::reject::Reject for ServiceError {}
+
+/// A running byte count exceeded the configured `--max-car-size` cap.
+/// Raised by `gateway::read_ipfs_to_local` (against bytes written to disk)
+/// and `commp::commp` (against decompressed bytes hashed) as a plain,
+/// downcastable `anyhow::Error` so `handler` can tell an over-size CAR
+/// apart from an ordinary fetch/CommP failure without either lower layer
+/// needing to know about `ServiceError`.
+#[derive(Debug)]
+pub struct CarTooLarge {
+    pub size: u64,
+    pub max: u64,
+}
+
+impl std::fmt::Display for CarTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CAR file size {} exceeds the {} byte limit", self.size, self.max)
+    }
+}
+
+impl std::error::Error for CarTooLarge {}
+
+impl ServiceError {
+    fn code(&self) -> &'static str {
+        match self {
+            ServiceError::BadCid(_) => "bad_cid",
+            ServiceError::GatewayFetch(_) => "gateway_fetch",
+            ServiceError::CarTooLarge { .. } => "car_too_large",
+            ServiceError::CommpFailed(_) => "commp_failed",
+            ServiceError::AskUnavailable(_) => "ask_unavailable",
+            ServiceError::PriceComputation(_) => "price_computation",
+            ServiceError::PriceTooHigh { .. } => "price_too_high",
+            ServiceError::DealRejected(_) => "deal_rejected",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            ServiceError::BadCid(_) | ServiceError::CarTooLarge { .. } => StatusCode::BAD_REQUEST,
+            ServiceError::GatewayFetch(e) => {
+                if e.downcast_ref::<reqwest::Error>()
+                    .map(|e| e.is_timeout())
+                    .unwrap_or(false)
+                {
+                    StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    StatusCode::BAD_GATEWAY
+                }
+            }
+            ServiceError::CommpFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ServiceError::AskUnavailable(_) => StatusCode::BAD_GATEWAY,
+            ServiceError::PriceComputation(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ServiceError::PriceTooHigh { .. } => StatusCode::PAYMENT_REQUIRED,
+            ServiceError::DealRejected(_) => StatusCode::CONFLICT,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+    code: &'static str,
+    detail: String,
+}
+
+/// Warp recovery filter: maps a rejected `ServiceError` to its HTTP status
+/// and a JSON `{ error, code, detail }` body.
+pub async fn recover(err: Rejection) -> Result<impl Reply, Infallible> {
+    if let Some(service_err) = err.find::<ServiceError>() {
+        let body = ErrorBody {
+            error: service_err.to_string(),
+            code: service_err.code(),
+            detail: format!("{service_err:?}"),
+        };
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&body),
+            service_err.status(),
+        ));
+    }
+
+    let status = if err.is_not_found() {
+        StatusCode::NOT_FOUND
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ErrorBody {
+            error: format!("{err:?}"),
+            code: "unhandled",
+            detail: String::new(),
+        }),
+        status,
+    ))
+}
@@ -0,0 +1,548 @@
+//! Resumable IPFS gateway fetch with exponential-backoff retry, with
+//! optional transparent gzip caching of the downloaded CAR.
+
+use std::io::{SeekFrom, Write};
+
+use anyhow::anyhow;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::warn;
+use reqwest::{header, StatusCode};
+use tempfile::NamedTempFile;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::error::CarTooLarge;
+use crate::retry::RetryConfig;
+
+/// Fixed part of the slack added to `max_car_size` when capping a
+/// gzip-encoded body's on-wire size: covers gzip's small fixed
+/// header/trailer overhead (18 bytes) with room to spare, and keeps small
+/// CARs from being rejected where the proportional term in
+/// `gzip_wire_size_cap` alone would round down to next to nothing.
+const GZIP_WIRE_SIZE_SLACK_BYTES: u64 = 4096;
+
+/// Upper bound on a gzip-encoded body's on-wire size for a given
+/// `max_car_size`. Gzip framing can make the wire size a little larger than
+/// the real, decompressed CAR size even though the decompressed size is
+/// what the cap is meant to bound — up to 5 bytes per 65535-byte stored
+/// block in the worst, incompressible case, or roughly `max / 13107`.
+/// `max / 10_000` is used instead: a deliberately looser, simpler-to-read
+/// ratio that still scales with `max`, so the cap stays generous enough for
+/// a legitimate CAR right at the limit however large `max_car_size` is,
+/// while still bounding disk usage for a gzip body whose wire size is
+/// outright huge or unbounded.
+fn gzip_wire_size_cap(max: u64) -> u64 {
+    max + max / 10_000 + GZIP_WIRE_SIZE_SLACK_BYTES
+}
+
+/// A downloaded CAR file, plus whether the bytes sitting on disk are
+/// gzip-compressed (either because the gateway served them that way, or
+/// because `--compress-cache` asked us to compress them after the fact).
+#[derive(Debug)]
+pub struct FetchedCar {
+    pub file: NamedTempFile,
+    pub gzip_compressed: bool,
+}
+
+/// Whether a failed attempt is worth retrying. `range_supported` carries
+/// whatever we learned about the gateway's `Range` support from the
+/// response headers before the failure, if we got that far, so a failure
+/// mid-stream doesn't throw that knowledge away.
+enum FetchError {
+    Retryable {
+        error: anyhow::Error,
+        range_supported: Option<bool>,
+    },
+    Permanent(anyhow::Error),
+}
+
+fn retryable(error: impl Into<anyhow::Error>, range_supported: Option<bool>) -> FetchError {
+    FetchError::Retryable {
+        error: error.into(),
+        range_supported,
+    }
+}
+
+struct AttemptOutcome {
+    gzip_encoded: bool,
+}
+
+async fn new_local_file() -> anyhow::Result<(NamedTempFile, std::fs::File)> {
+    Ok(tokio::task::spawn_blocking(|| {
+        let temp_file = NamedTempFile::new()?;
+        let file_fd = temp_file.reopen()?;
+        Result::<(NamedTempFile, std::fs::File), std::io::Error>::Ok((temp_file, file_fd))
+    })
+    .await??)
+}
+
+/// Streams one fetch attempt into `file`, resuming from `written` bytes via
+/// a `Range` request once the gateway has told us it supports one. Bails
+/// out with `FetchError::Permanent(CarTooLarge)` as soon as the running
+/// byte count — the advertised `Content-Length` or the bytes actually
+/// written — crosses `max_car_size`, instead of writing the whole body to
+/// disk first and checking afterwards. A gzip-encoded body's wire size
+/// isn't the real, decompressed CAR size the cap is meant to bound, so it's
+/// checked against `gzip_wire_size_cap(max_car_size)` instead — loose enough
+/// to tolerate gzip framing overhead, tight enough to still bound disk usage
+/// during the download itself. `commp::commp` separately
+/// enforces the real cap against the decompressed stream it hashes.
+async fn fetch_once(
+    ipfs_url: &str,
+    written: u64,
+    supports_range: bool,
+    file: &mut File,
+    max_car_size: Option<u64>,
+) -> Result<AttemptOutcome, FetchError> {
+    let client = reqwest::Client::new();
+    let mut req = client.get(ipfs_url);
+    if written > 0 && supports_range {
+        req = req.header(header::RANGE, format!("bytes={}-", written));
+    }
+
+    let mut resp = req.send().await.map_err(|e| retryable(e, None))?;
+
+    let status = resp.status();
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(retryable(anyhow!("gateway returned {status}"), None));
+    }
+    if status.is_client_error() {
+        return Err(FetchError::Permanent(anyhow!("gateway returned {status}")));
+    }
+
+    let range_honored = status == StatusCode::PARTIAL_CONTENT;
+    let gateway_supports_range = range_honored
+        || resp
+            .headers()
+            .get(header::ACCEPT_RANGES)
+            .map(|v| v == "bytes")
+            .unwrap_or(false);
+    let gzip_encoded = resp
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .map(|v| v == "gzip")
+        .unwrap_or(false);
+
+    if written > 0 && !range_honored {
+        // The gateway ignored our Range request; start the file over.
+        file.seek(SeekFrom::Start(0))
+            .await
+            .map_err(|e| retryable(e, Some(gateway_supports_range)))?;
+        file.set_len(0)
+            .await
+            .map_err(|e| retryable(e, Some(gateway_supports_range)))?;
+    }
+
+    // Bytes already on disk once this attempt's body starts landing: the
+    // resumed offset if the gateway honored our Range request, zero if we
+    // just truncated the file (or this is the first attempt).
+    let mut total = if written > 0 && range_honored {
+        written
+    } else {
+        0
+    };
+
+    // Loosen the cap for a gzip body: its wire bytes aren't the real CAR
+    // size `max_car_size` means to bound, but they still need *some* bound
+    // so a huge or unbounded gzip stream can't exhaust disk before
+    // `commp::commp` gets a chance to check the decompressed size.
+    let max_car_size = if gzip_encoded {
+        max_car_size.map(gzip_wire_size_cap)
+    } else {
+        max_car_size
+    };
+
+    if let (Some(max), Some(content_len)) = (max_car_size, resp.content_length()) {
+        let expected_total = total + content_len;
+        if expected_total > max {
+            return Err(FetchError::Permanent(
+                CarTooLarge {
+                    size: expected_total,
+                    max,
+                }
+                .into(),
+            ));
+        }
+    }
+
+    loop {
+        match resp.chunk().await {
+            Ok(Some(chunk)) => {
+                total += chunk.len() as u64;
+                if let Some(max) = max_car_size {
+                    if total > max {
+                        return Err(FetchError::Permanent(
+                            CarTooLarge { size: total, max }.into(),
+                        ));
+                    }
+                }
+                file.write_all(&chunk)
+                    .await
+                    .map_err(|e| retryable(e, Some(gateway_supports_range)))?
+            }
+            Ok(None) => return Ok(AttemptOutcome { gzip_encoded }),
+            Err(e) => return Err(retryable(e, Some(gateway_supports_range))),
+        }
+    }
+}
+
+/// Gzip-compresses `file` in place, replacing its contents with the
+/// compressed form. Used when the gateway didn't already serve a
+/// compressed body but `--compress-cache` was requested.
+async fn compress_in_place(file: &mut File, path: std::path::PathBuf) -> anyhow::Result<()> {
+    let compressed = tokio::task::spawn_blocking(move || -> anyhow::Result<Vec<u8>> {
+        let raw = std::fs::read(&path)?;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&raw)?;
+        Ok(encoder.finish()?)
+    })
+    .await??;
+
+    file.seek(SeekFrom::Start(0)).await?;
+    file.set_len(0).await?;
+    file.write_all(&compressed).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// Downloads `ipfs_url` to a local temp file, retrying transient failures
+/// (connection resets, timeouts, 429/5xx) with exponential backoff and
+/// resuming via `Range` requests when the gateway supports them. A 4xx
+/// other than 429 is treated as permanent and returned immediately.
+///
+/// When the gateway serves the CAR with `Content-Encoding: gzip`, the
+/// compressed bytes are written straight to disk. If it doesn't, but
+/// `compress_cache` is set, the temp file is gzip-compressed after the
+/// download completes to cut temp-disk usage.
+///
+/// `max_car_size`, if set, bounds the bytes written to disk on this pass —
+/// checked incrementally as the body streams in, not after the whole file
+/// has landed. A gzip-encoded body's on-wire size doesn't reflect the real
+/// CAR size, so it's bounded by `gzip_wire_size_cap(max_car_size)` instead,
+/// loose enough to tolerate gzip framing overhead; `commp::commp` separately
+/// caps the decompressed stream it hashes, which is what actually bounds a
+/// gzip-encoded download's real size.
+pub async fn read_ipfs_to_local(
+    ipfs_url: &str,
+    retry: &RetryConfig,
+    compress_cache: bool,
+    max_car_size: Option<u64>,
+) -> anyhow::Result<FetchedCar> {
+    let (temp_file, file_fd) = new_local_file().await?;
+    let mut local_file = File::from_std(file_fd);
+
+    let mut supports_range = false;
+    let mut attempt = 0u32;
+
+    loop {
+        let written = local_file.metadata().await.map(|m| m.len()).unwrap_or(0);
+
+        match fetch_once(
+            ipfs_url,
+            written,
+            supports_range,
+            &mut local_file,
+            max_car_size,
+        )
+        .await
+        {
+            Ok(outcome) => {
+                let mut gzip_compressed = outcome.gzip_encoded;
+                if !gzip_compressed && compress_cache {
+                    compress_in_place(&mut local_file, temp_file.path().to_path_buf()).await?;
+                    gzip_compressed = true;
+                }
+                return Ok(FetchedCar {
+                    file: temp_file,
+                    gzip_compressed,
+                });
+            }
+            Err(FetchError::Permanent(e)) => return Err(e),
+            Err(FetchError::Retryable {
+                error,
+                range_supported,
+            }) => {
+                if let Some(range_supported) = range_supported {
+                    supports_range = range_supported;
+                }
+                if attempt >= retry.max_retries {
+                    return Err(error);
+                }
+                let delay = retry.backoff(attempt);
+                warn!(
+                    "ipfs gateway fetch failed (attempt {}/{}): {error}; retrying in {:?}",
+                    attempt + 1,
+                    retry.max_retries,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// What a mock connection does once it's read the request: reply with a
+    /// full HTTP response, or close without writing one (reqwest surfaces
+    /// this as a retryable connection error), or write a truncated response
+    /// — headers promising more body than actually arrives — to simulate a
+    /// connection reset mid-transfer.
+    enum MockResponse {
+        Reply {
+            status: u16,
+            extra_headers: Vec<(&'static str, String)>,
+            body: Vec<u8>,
+        },
+        Truncated {
+            full_len: usize,
+            sent_body: Vec<u8>,
+            extra_headers: Vec<(&'static str, String)>,
+        },
+        Close,
+    }
+
+    /// A minimal single-purpose HTTP/1.1 server: reads just enough of a
+    /// request to hand the handler its `Range` header (if any), and writes
+    /// back whatever `MockResponse` the handler returns. This is enough to
+    /// drive `read_ipfs_to_local`'s retry/resume state machine end-to-end
+    /// without a mock-HTTP dev-dependency.
+    fn spawn_mock_gateway<F>(handler: F) -> String
+    where
+        F: Fn(Option<String>) -> MockResponse + Send + Sync + 'static,
+    {
+        let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        std_listener.set_nonblocking(true).unwrap();
+        let listener = TcpListener::from_std(std_listener).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(handler);
+
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let handler = handler.clone();
+                tokio::spawn(serve_one(stream, handler));
+            }
+        });
+
+        format!("http://{addr}")
+    }
+
+    async fn serve_one<F>(mut stream: TcpStream, handler: Arc<F>)
+    where
+        F: Fn(Option<String>) -> MockResponse + Send + Sync + 'static,
+    {
+        let range = read_range_header(&mut stream).await;
+        let response = handler(range);
+        match response {
+            MockResponse::Reply {
+                status,
+                extra_headers,
+                body,
+            } => {
+                write_head(&mut stream, status, body.len(), &extra_headers).await;
+                let _ = stream.write_all(&body).await;
+            }
+            MockResponse::Truncated {
+                full_len,
+                sent_body,
+                extra_headers,
+            } => {
+                write_head(&mut stream, 200, full_len, &extra_headers).await;
+                let _ = stream.write_all(&sent_body).await;
+                // Drop the connection instead of writing the rest of the
+                // promised body, simulating a reset mid-transfer.
+            }
+            MockResponse::Close => {}
+        }
+    }
+
+    async fn write_head(
+        stream: &mut TcpStream,
+        status: u16,
+        body_len: usize,
+        extra_headers: &[(&'static str, String)],
+    ) {
+        let reason = match status {
+            200 => "OK",
+            206 => "Partial Content",
+            404 => "Not Found",
+            429 => "Too Many Requests",
+            500 => "Internal Server Error",
+            _ => "Unknown",
+        };
+        let mut head = format!("HTTP/1.1 {status} {reason}\r\ncontent-length: {body_len}\r\n");
+        for (name, value) in extra_headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("connection: close\r\n\r\n");
+        let _ = stream.write_all(head.as_bytes()).await;
+    }
+
+    /// Reads a request line and headers up to the blank line that ends
+    /// them, returning the `Range` header's value if the client sent one.
+    async fn read_range_header(stream: &mut TcpStream) -> Option<String> {
+        let mut buf = Vec::new();
+        let mut byte = [0u8; 1];
+        loop {
+            if stream.read_exact(&mut byte).await.is_err() {
+                return None;
+            }
+            buf.push(byte[0]);
+            if buf.ends_with(b"\r\n\r\n") {
+                break;
+            }
+        }
+        String::from_utf8_lossy(&buf)
+            .lines()
+            .find(|line| line.to_ascii_lowercase().starts_with("range:"))
+            .map(|line| line.split_once(':').unwrap().1.trim().to_string())
+    }
+
+    /// Tight retry timings so these tests don't spend real wall-clock time
+    /// sleeping through backoff delays.
+    fn fast_retry() -> RetryConfig {
+        RetryConfig {
+            max_retries: 3,
+            retry_base_delay_ms: 1,
+            retry_max_delay_ms: 2,
+            retry_multiplier: 1.0,
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_failure_is_retried_until_success() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let handler_attempts = attempts.clone();
+        let url = spawn_mock_gateway(move |_range| {
+            if handler_attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                MockResponse::Close
+            } else {
+                MockResponse::Reply {
+                    status: 200,
+                    extra_headers: vec![],
+                    body: b"car-bytes".to_vec(),
+                }
+            }
+        });
+
+        let fetched = read_ipfs_to_local(&url, &fast_retry(), false, None)
+            .await
+            .expect("should succeed after one retry");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        let contents = tokio::fs::read(fetched.file.path()).await.unwrap();
+        assert_eq!(contents, b"car-bytes");
+    }
+
+    #[tokio::test]
+    async fn permanent_4xx_is_returned_without_retrying() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let handler_attempts = attempts.clone();
+        let url = spawn_mock_gateway(move |_range| {
+            handler_attempts.fetch_add(1, Ordering::SeqCst);
+            MockResponse::Reply {
+                status: 404,
+                extra_headers: vec![],
+                body: vec![],
+            }
+        });
+
+        let err = read_ipfs_to_local(&url, &fast_retry(), false, None)
+            .await
+            .expect_err("a non-429 4xx must not be retried");
+        assert!(err.to_string().contains("404"));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn ignored_range_request_restarts_the_file() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let handler_attempts = attempts.clone();
+        let full_body = b"brand-new-full-car-bytes".to_vec();
+        let full_body_for_handler = full_body.clone();
+
+        let url = spawn_mock_gateway(move |range| {
+            if handler_attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                // First attempt: write a handful of bytes, then reset the
+                // connection, advertising Range support so the client
+                // attempts a resumed request next.
+                MockResponse::Truncated {
+                    full_len: 999,
+                    sent_body: b"stale".to_vec(),
+                    extra_headers: vec![("accept-ranges", "bytes".to_string())],
+                }
+            } else {
+                // This gateway ignores the client's Range request (replies
+                // 200, not 206) and serves the full, current body instead.
+                assert!(
+                    range.is_some(),
+                    "expected the resumed attempt to carry a Range header"
+                );
+                MockResponse::Reply {
+                    status: 200,
+                    extra_headers: vec![],
+                    body: full_body_for_handler.clone(),
+                }
+            }
+        });
+
+        let fetched = read_ipfs_to_local(&url, &fast_retry(), false, None)
+            .await
+            .expect("should recover once the ignored-Range path restarts the file");
+        let contents = tokio::fs::read(fetched.file.path()).await.unwrap();
+        assert_eq!(
+            contents, full_body,
+            "stale bytes from the reset attempt must be discarded, not appended to"
+        );
+    }
+
+    #[tokio::test]
+    async fn max_car_size_tolerates_small_gzip_wire_overhead() {
+        // The body's on-wire (compressed) length exceeds max_car_size by a
+        // few bytes — plausible gzip framing overhead on a real CAR that's
+        // actually within the cap — so `fetch_once` must let it through,
+        // within the `gzip_wire_size_cap` allowance.
+        let body = vec![0u8; 32];
+        let url = spawn_mock_gateway(move |_range| MockResponse::Reply {
+            status: 200,
+            extra_headers: vec![("content-encoding", "gzip".to_string())],
+            body: body.clone(),
+        });
+
+        let fetched = read_ipfs_to_local(&url, &fast_retry(), false, Some(16))
+            .await
+            .expect("a gzip body within the slack allowance must not be rejected");
+        assert!(fetched.gzip_compressed);
+    }
+
+    #[tokio::test]
+    async fn max_car_size_still_bounds_a_hugely_oversized_gzip_body() {
+        // An outright huge gzip wire size — far beyond any plausible
+        // framing overhead — must still be rejected, so disk can't be
+        // exhausted before `commp::commp` ever runs. Comfortably bigger
+        // than `gzip_wire_size_cap(16)`.
+        let body = vec![0u8; 8192];
+        let url = spawn_mock_gateway(move |_range| MockResponse::Reply {
+            status: 200,
+            extra_headers: vec![("content-encoding", "gzip".to_string())],
+            body: body.clone(),
+        });
+
+        let err = read_ipfs_to_local(&url, &fast_retry(), false, Some(16))
+            .await
+            .expect_err("a wildly oversized gzip body must still be capped");
+        assert!(err.downcast_ref::<CarTooLarge>().is_some());
+    }
+}
@@ -1,19 +1,29 @@
-use std::fmt::{Debug, Formatter};
-use std::io;
+use std::collections::HashMap;
 use std::io::{BufRead, Cursor};
-use std::mem::MaybeUninit;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 use anyhow::anyhow;
+use cid::Cid;
 use clap::Parser;
 use serde::Serialize;
-use tempfile::NamedTempFile;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
 use warp::reply::Json;
 use warp::{Filter, Rejection};
 
+mod commp;
+mod error;
+mod gateway;
+mod pricing;
+mod retry;
+mod tracker;
+
+use error::{CarTooLarge, ServiceError};
+use retry::RetryConfig;
+use tracker::{DealState, DealTracker};
+
 #[derive(Parser)]
 #[clap(version)]
 struct Args {
@@ -26,18 +36,52 @@ struct Args {
     /// Miner id
     #[clap(short, long)]
     miner_id: String,
+    #[clap(flatten)]
+    retry: RetryConfig,
+    /// Reject the deal if the computed per-epoch price exceeds this amount
+    #[clap(long)]
+    max_price_per_epoch: Option<usize>,
+    /// Multiplier applied to the provider's advertised ask, to optionally bid above it
+    #[clap(long, default_value_t = 1.0)]
+    price_multiplier: f64,
+    /// Price and propose deals as verified (DataCap), using the ask's
+    /// verified price instead of its regular price
+    #[clap(long)]
+    verified: bool,
+    /// Reject CAR files larger than this many bytes
+    #[clap(long)]
+    max_car_size: Option<u64>,
+    /// Interval, in seconds, between deal-status polls
+    #[clap(long, default_value_t = 30)]
+    deal_status_poll_interval_secs: u64,
+    /// Gzip-compress the cached CAR file on disk when the gateway doesn't already
+    #[clap(long)]
+    compress_cache: bool,
 }
 
-static mut ARGS: MaybeUninit<Args> = MaybeUninit::uninit();
+static ARGS: OnceLock<Args> = OnceLock::new();
 
 fn set_args(args: Args) {
-    unsafe {
-        ARGS.write(args);
-    }
+    ARGS.set(args)
+        .unwrap_or_else(|_| panic!("set_args called more than once"));
 }
 
 fn get_args() -> &'static Args {
-    unsafe { ARGS.assume_init_ref() }
+    ARGS.get().expect("set_args must run before get_args")
+}
+
+static TRACKER: OnceLock<DealTracker> = OnceLock::new();
+
+fn set_tracker(tracker: DealTracker) {
+    TRACKER
+        .set(tracker)
+        .unwrap_or_else(|_| panic!("set_tracker called more than once"));
+}
+
+fn get_tracker() -> &'static DealTracker {
+    TRACKER
+        .get()
+        .expect("set_tracker must run before get_tracker")
 }
 
 struct DealCMD {
@@ -48,15 +92,10 @@ struct DealCMD {
     piece_size: usize,
     payload_cid: String,
     storage_price_per_epoch: usize,
+    max_price_per_epoch: Option<usize>,
     verified: bool,
 }
 
-struct CommpRes {
-    commp_cid: String,
-    piece_size: usize,
-    car_file_size: usize,
-}
-
 #[derive(Serialize)]
 struct DealRes {
     deal_uuid: String,
@@ -70,38 +109,7 @@ struct DealRes {
     provider_collateral: String,
 }
 
-struct CustomReject(anyhow::Error);
-
-impl Debug for CustomReject {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
-    }
-}
-
-impl warp::reject::Reject for CustomReject {}
-
-fn custom_reject(error: impl Into<anyhow::Error>) -> Rejection {
-    warp::reject::custom(CustomReject(error.into()))
-}
-
-async fn read_ipfs_to_local(ipfs_url: &str) -> anyhow::Result<NamedTempFile> {
-    let mut resp = reqwest::get(ipfs_url).await?;
-
-    let (temp_file, file_fd) = tokio::task::spawn_blocking(move || {
-        let temp_file = NamedTempFile::new()?;
-        let file_fd = temp_file.reopen()?;
-        Result::<(NamedTempFile, std::fs::File), io::Error>::Ok((temp_file, file_fd))
-    })
-    .await??;
-
-    let mut local_file = File::from_std(file_fd);
-
-    while let Some(chunk) = resp.chunk().await? {
-        local_file.write_all(&chunk).await?
-    }
-    Ok(temp_file)
-}
-
+#[macro_export]
 macro_rules! read_line {
     ($lines: expr, $err_msg: expr) => {{
         let mut f = || -> anyhow::Result<String> {
@@ -115,6 +123,7 @@ macro_rules! read_line {
     }};
 }
 
+#[macro_export]
 macro_rules! resolve_next_line {
     ($match: expr, $lines: expr, $err_msg: expr) => {{
         let mut f = || -> anyhow::Result<String> {
@@ -127,42 +136,11 @@ macro_rules! resolve_next_line {
     }};
 }
 
-async fn commp(file: NamedTempFile) -> anyhow::Result<CommpRes> {
-    let output = tokio::process::Command::new("boostx")
-        .arg("commp")
-        .arg(file.path())
-        .output()
-        .await?;
-
-    if !output.status.success() {
-        return Err(anyhow!(String::from_utf8(output.stderr)?));
-    }
-
-    let mut lines = Cursor::new(output.stdout).lines();
-    let commp_cid: String =
-        resolve_next_line!("CommP CID: {}", lines, "Resolve commp cid failure")?;
-
-    const RESOLVE_PIECE_SIZE_FAILURE: &str = "Resolve piece size failure";
-    let piece_size: String =
-        resolve_next_line!("Piece size: {}", lines, RESOLVE_PIECE_SIZE_FAILURE)?;
-    let piece_size =
-        usize::from_str(&piece_size).map_err(|_| anyhow!(RESOLVE_PIECE_SIZE_FAILURE))?;
-
-    const RESOLVE_CAR_FILE_SIZE_FAILURE: &str = "Resolve car file size failure";
-    let car_file_size: String =
-        resolve_next_line!("Car file size: {}", lines, RESOLVE_CAR_FILE_SIZE_FAILURE)?;
-    let car_file_size =
-        usize::from_str(&car_file_size).map_err(|_| anyhow!(RESOLVE_CAR_FILE_SIZE_FAILURE))?;
-
-    let commp_res = CommpRes {
-        commp_cid,
-        piece_size,
-        car_file_size,
-    };
-    Ok(commp_res)
-}
-
 async fn deal(mut cmd: DealCMD) -> anyhow::Result<DealRes> {
+    // `cmd.storage_price_per_epoch` is already priced against the
+    // provider's current ask, so this should succeed on the first try.
+    // The retry-on-rejection-text loop below only exists to cover the
+    // case where the ask moved between `fetch_ask` and this call.
     let output = loop {
         let output = tokio::process::Command::new("boost")
             .arg("deal")
@@ -184,12 +162,25 @@ async fn deal(mut cmd: DealCMD) -> anyhow::Result<DealRes> {
             let err = String::from_utf8(output.stderr)?;
 
             if err.contains("storage price per epoch less than asking price") {
-                let str = err.split(':').last().ok_or_else(|| anyhow!(err.clone()))?;
+                let str = err
+                    .split(':')
+                    .next_back()
+                    .ok_or_else(|| anyhow!(err.clone()))?;
                 let str = str.trim();
                 let storage_price_per_epoch: String =
                     sscanf::scanf!(str, "0 < {}", String).map_err(|_| anyhow!(err.clone()))?;
                 cmd.storage_price_per_epoch =
                     usize::from_str(storage_price_per_epoch.trim()).map_err(|_| anyhow!(err))?;
+
+                if let Some(max_price_per_epoch) = cmd.max_price_per_epoch {
+                    if cmd.storage_price_per_epoch > max_price_per_epoch {
+                        return Err(anyhow!(
+                            "updated ask price {} per epoch exceeds configured cap {}",
+                            cmd.storage_price_per_epoch,
+                            max_price_per_epoch
+                        ));
+                    }
+                }
                 continue;
             } else {
                 return Err(anyhow!(err));
@@ -250,11 +241,62 @@ async fn deal(mut cmd: DealCMD) -> anyhow::Result<DealRes> {
     Ok(res)
 }
 
+/// Recovers a `CarTooLarge` raised deep inside the gateway/CommP pipeline
+/// into `ServiceError::CarTooLarge`, falling back to `otherwise` for any
+/// other `anyhow::Error` from that stage.
+fn too_large_or(
+    err: anyhow::Error,
+    otherwise: impl FnOnce(anyhow::Error) -> ServiceError,
+) -> ServiceError {
+    match err.downcast::<CarTooLarge>() {
+        Ok(CarTooLarge { size, max }) => ServiceError::CarTooLarge { size, max },
+        Err(err) => otherwise(err),
+    }
+}
+
 async fn handler(cid: String) -> Result<Json, Rejection> {
     let fut = async move {
+        Cid::try_from(cid.as_str()).map_err(|_| ServiceError::BadCid(cid.clone()))?;
+
         let ipfs_url = format!("{}/api/v0/dag/export?arg={}", get_args().ipfs_gateway, cid);
-        let file = read_ipfs_to_local(&ipfs_url).await?;
-        let commp = commp(file).await?;
+        // Both stages below enforce `max_car_size` incrementally as bytes
+        // stream through them, not just at EOF: `read_ipfs_to_local` bounds
+        // the (possibly still-compressed) bytes it writes to disk, and
+        // `commp::commp` separately bounds the decompressed bytes it hashes,
+        // since a gzipped cache file can't be size-checked on disk alone.
+        let fetched = gateway::read_ipfs_to_local(
+            &ipfs_url,
+            &get_args().retry,
+            get_args().compress_cache,
+            get_args().max_car_size,
+        )
+        .await
+        .map_err(|e| too_large_or(e, ServiceError::GatewayFetch))?;
+
+        let commp = commp::commp(&fetched.file, fetched.gzip_compressed, get_args().max_car_size)
+            .await
+            .map_err(|e| too_large_or(e, ServiceError::CommpFailed))?;
+
+        let verified = get_args().verified;
+        let ask = pricing::fetch_ask(&get_args().miner_id)
+            .await
+            .map_err(ServiceError::AskUnavailable)?;
+        let storage_price_per_epoch = pricing::price_per_epoch(
+            &ask,
+            commp.piece_size,
+            verified,
+            get_args().price_multiplier,
+        )
+        .map_err(ServiceError::PriceComputation)?;
+
+        if let Some(max_price_per_epoch) = get_args().max_price_per_epoch {
+            if storage_price_per_epoch > max_price_per_epoch {
+                return Err(ServiceError::PriceTooHigh {
+                    price: storage_price_per_epoch,
+                    max: max_price_per_epoch,
+                });
+            }
+        }
 
         let cmd = DealCMD {
             provider: get_args().miner_id.clone(),
@@ -263,20 +305,46 @@ async fn handler(cid: String) -> Result<Json, Rejection> {
             car_size: commp.car_file_size,
             piece_size: commp.piece_size,
             payload_cid: cid,
-            storage_price_per_epoch: 0,
-            verified: false,
+            storage_price_per_epoch,
+            max_price_per_epoch: get_args().max_price_per_epoch,
+            verified,
         };
 
-        let res = deal(cmd).await?;
-        Result::<Json, anyhow::Error>::Ok(warp::reply::json(&res))
+        let res = deal(cmd).await.map_err(ServiceError::DealRejected)?;
+
+        if let Ok(deal_uuid) = Uuid::parse_str(&res.deal_uuid) {
+            tracker::track(
+                get_tracker().clone(),
+                deal_uuid,
+                Duration::from_secs(get_args().deal_status_poll_interval_secs),
+            );
+        }
+
+        Result::<Json, ServiceError>::Ok(warp::reply::json(&res))
     };
 
-    fut.await.map_err(custom_reject)
+    fut.await.map_err(warp::reject::custom)
+}
+
+async fn get_deal_status(deal_uuid: Uuid) -> Result<Json, Rejection> {
+    get_tracker()
+        .get(&deal_uuid)
+        .map(|state| warp::reply::json(&*state))
+        .ok_or_else(warp::reject::not_found)
+}
+
+async fn list_deals() -> Result<Json, Rejection> {
+    let deals: HashMap<Uuid, DealState> = get_tracker()
+        .iter()
+        .map(|entry| (*entry.key(), entry.value().clone()))
+        .collect();
+    Ok(warp::reply::json(&deals))
 }
 
 #[tokio::main]
 async fn main() {
     set_args(Args::parse());
+    set_tracker(tracker::new_tracker());
     env_logger::init_from_env(
         env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
     );
@@ -286,5 +354,14 @@ async fn main() {
         .and(warp::path::param())
         .and_then(handler);
 
-    warp::serve(promote).run(get_args().listen_addr).await
+    let deal_status = warp::get()
+        .and(warp::path("deal"))
+        .and(warp::path::param())
+        .and_then(get_deal_status);
+
+    let deals = warp::get().and(warp::path("deals")).and_then(list_deals);
+
+    let routes = promote.or(deal_status).or(deals).recover(error::recover);
+
+    warp::serve(routes).run(get_args().listen_addr).await
 }
@@ -0,0 +1,117 @@
+//! Deal pricing: queries the provider's current storage ask up front
+//! instead of discovering the price by provoking and parsing a rejection.
+
+use std::io::{BufRead, Cursor};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+use crate::{read_line, resolve_next_line};
+
+const BYTES_PER_GIB: u128 = 1 << 30;
+
+/// A storage provider's advertised ask, as reported by `boost provider`.
+pub struct Ask {
+    pub price_per_gib_per_epoch: u128,
+    pub verified_price_per_gib_per_epoch: u128,
+}
+
+/// Fetches `provider`'s current storage ask.
+pub async fn fetch_ask(provider: &str) -> anyhow::Result<Ask> {
+    let output = tokio::process::Command::new("boost")
+        .arg("provider")
+        .args(["get-ask", provider])
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(anyhow!(String::from_utf8(output.stderr)?));
+    }
+
+    let mut lines = Cursor::new(output.stdout).lines();
+
+    const RESOLVE_PRICE_FAILURE: &str = "Resolve ask price failure";
+    let price: String =
+        resolve_next_line!("Price per GiB per epoch: {}", lines, RESOLVE_PRICE_FAILURE)?;
+    let price_per_gib_per_epoch =
+        u128::from_str(&price).map_err(|_| anyhow!(RESOLVE_PRICE_FAILURE))?;
+
+    const RESOLVE_VERIFIED_PRICE_FAILURE: &str = "Resolve verified ask price failure";
+    let verified_price: String = resolve_next_line!(
+        "Verified price per GiB per epoch: {}",
+        lines,
+        RESOLVE_VERIFIED_PRICE_FAILURE
+    )?;
+    let verified_price_per_gib_per_epoch =
+        u128::from_str(&verified_price).map_err(|_| anyhow!(RESOLVE_VERIFIED_PRICE_FAILURE))?;
+
+    Ok(Ask {
+        price_per_gib_per_epoch,
+        verified_price_per_gib_per_epoch,
+    })
+}
+
+/// Computes the per-epoch price for a piece of `piece_size` bytes at
+/// `ask`'s advertised rate, scaled by `multiplier` (to bid slightly above
+/// ask if the operator chooses to).
+pub fn price_per_epoch(
+    ask: &Ask,
+    piece_size: usize,
+    verified: bool,
+    multiplier: f64,
+) -> anyhow::Result<usize> {
+    let price_per_gib_per_epoch = if verified {
+        ask.verified_price_per_gib_per_epoch
+    } else {
+        ask.price_per_gib_per_epoch
+    };
+
+    let base = price_per_gib_per_epoch
+        .checked_mul(piece_size as u128)
+        .ok_or_else(|| anyhow!("price overflow computing per-epoch price"))?
+        / BYTES_PER_GIB;
+
+    let scaled = (base as f64 * multiplier).ceil();
+    if scaled < 0.0 || scaled > usize::MAX as f64 {
+        return Err(anyhow!("price overflow computing per-epoch price"));
+    }
+    Ok(scaled as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ask() -> Ask {
+        Ask {
+            price_per_gib_per_epoch: 2 * BYTES_PER_GIB,
+            verified_price_per_gib_per_epoch: BYTES_PER_GIB,
+        }
+    }
+
+    #[test]
+    fn scales_by_piece_size_and_multiplier() {
+        let piece_size = BYTES_PER_GIB as usize;
+        let price = price_per_epoch(&ask(), piece_size, false, 1.0).unwrap();
+        assert_eq!(price, 2 * BYTES_PER_GIB as usize);
+
+        let price = price_per_epoch(&ask(), piece_size, false, 1.5).unwrap();
+        assert_eq!(price, 3 * BYTES_PER_GIB as usize);
+    }
+
+    #[test]
+    fn verified_uses_the_verified_rate() {
+        let piece_size = BYTES_PER_GIB as usize;
+        let price = price_per_epoch(&ask(), piece_size, true, 1.0).unwrap();
+        assert_eq!(price, BYTES_PER_GIB as usize);
+    }
+
+    #[test]
+    fn rejects_overflowing_prices() {
+        let huge_ask = Ask {
+            price_per_gib_per_epoch: u128::MAX,
+            verified_price_per_gib_per_epoch: u128::MAX,
+        };
+        assert!(price_per_epoch(&huge_ask, usize::MAX, false, 1.0).is_err());
+    }
+}
@@ -0,0 +1,72 @@
+//! Exponential-backoff retry policy shared by outbound network calls.
+
+use std::time::Duration;
+
+use clap::Args as ClapArgs;
+use rand::Rng;
+
+#[derive(ClapArgs, Clone, Copy, Debug)]
+pub struct RetryConfig {
+    /// Maximum number of retry attempts before giving up
+    #[clap(long, default_value_t = 5)]
+    pub max_retries: u32,
+    /// Base delay before the first retry, in milliseconds
+    #[clap(long, default_value_t = 500)]
+    pub retry_base_delay_ms: u64,
+    /// Upper bound on the backoff delay, in milliseconds
+    #[clap(long, default_value_t = 30_000)]
+    pub retry_max_delay_ms: u64,
+    /// Multiplier applied to the delay after each failed attempt
+    #[clap(long, default_value_t = 2.0)]
+    pub retry_multiplier: f64,
+}
+
+impl RetryConfig {
+    /// `min(base * multiplier^attempt, max)` plus up to 20% random jitter,
+    /// so a thundering herd of retries doesn't hit the gateway in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let base = self.retry_base_delay_ms as f64;
+        let uncapped = base * self.retry_multiplier.powi(attempt as i32);
+        let capped = uncapped.min(self.retry_max_delay_ms as f64);
+
+        let jitter = rand::thread_rng().gen_range(0.0..=capped * 0.2);
+        Duration::from_millis((capped + jitter) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RetryConfig {
+        RetryConfig {
+            max_retries: 5,
+            retry_base_delay_ms: 500,
+            retry_max_delay_ms: 30_000,
+            retry_multiplier: 2.0,
+        }
+    }
+
+    #[test]
+    fn backoff_grows_geometrically_up_to_the_cap() {
+        let config = config();
+        for attempt in 0..6 {
+            let uncapped = 500.0 * 2f64.powi(attempt);
+            let expected_floor = uncapped.min(30_000.0) as u64;
+            let delay = config.backoff(attempt as u32).as_millis() as u64;
+            assert!(
+                delay >= expected_floor && delay <= expected_floor + expected_floor / 5 + 1,
+                "attempt {attempt}: delay {delay} out of range around floor {expected_floor}"
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_never_exceeds_max_delay_plus_jitter() {
+        let config = config();
+        for attempt in 0..20 {
+            let delay = config.backoff(attempt).as_millis() as u64;
+            assert!(delay <= config.retry_max_delay_ms + config.retry_max_delay_ms / 5 + 1);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Background deal-status tracking.
+//!
+//! Once `deal()` returns, the service used to lose track of whether the
+//! transfer completed, the deal was published on chain, or it was
+//! rejected later. This module polls `boost deal-status` in the
+//! background and keeps the latest checkpoint in a shared map so callers
+//! can check on a deal instead of only ever seeing its initial proposal.
+
+use std::io::{BufRead, Cursor};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use dashmap::DashMap;
+use log::warn;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{read_line, resolve_next_line};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Checkpoint {
+    Proposed,
+    Transferred,
+    Published,
+    Active,
+    Failed,
+}
+
+impl Checkpoint {
+    fn is_terminal(self) -> bool {
+        matches!(self, Checkpoint::Active | Checkpoint::Failed)
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct DealState {
+    pub checkpoint: Checkpoint,
+    pub updated_at: u64,
+    pub error: Option<String>,
+}
+
+pub type DealTracker = Arc<DashMap<Uuid, DealState>>;
+
+pub fn new_tracker() -> DealTracker {
+    Arc::new(DashMap::new())
+}
+
+/// Spawns a background task that polls `boost deal-status` for
+/// `deal_uuid` every `poll_interval` until it reaches a terminal
+/// checkpoint (`Active` or `Failed`).
+pub fn track(tracker: DealTracker, deal_uuid: Uuid, poll_interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            match fetch_deal_status(deal_uuid).await {
+                Ok(state) => {
+                    let terminal = state.checkpoint.is_terminal();
+                    tracker.insert(deal_uuid, state);
+                    if terminal {
+                        break;
+                    }
+                }
+                Err(e) => warn!("failed to poll deal status for {deal_uuid}: {e}"),
+            }
+            tokio::time::sleep(poll_interval).await;
+        }
+    });
+}
+
+/// Maps one of `boost deal-status`'s checkpoint strings onto our own
+/// `Checkpoint`, collapsing the handful of synonyms `boost` reports for
+/// each stage.
+fn parse_checkpoint(checkpoint: &str) -> anyhow::Result<Checkpoint> {
+    match checkpoint {
+        "Accepted" | "Proposed" => Ok(Checkpoint::Proposed),
+        "Transferred" => Ok(Checkpoint::Transferred),
+        "Published" | "PublishConfirmed" => Ok(Checkpoint::Published),
+        "Active" | "Complete" => Ok(Checkpoint::Active),
+        "Failed" | "Error" => Ok(Checkpoint::Failed),
+        other => Err(anyhow!("unrecognized deal checkpoint: {other}")),
+    }
+}
+
+async fn fetch_deal_status(deal_uuid: Uuid) -> anyhow::Result<DealState> {
+    let output = tokio::process::Command::new("boost")
+        .arg("deal-status")
+        .args(["--deal-uuid", &deal_uuid.to_string()])
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(anyhow!(String::from_utf8(output.stderr)?));
+    }
+
+    let mut lines = Cursor::new(output.stdout).lines();
+    const RESOLVE_CHECKPOINT_FAILURE: &str = "Resolve deal checkpoint failure";
+    let checkpoint: String =
+        resolve_next_line!("checkpoint: {}", lines, RESOLVE_CHECKPOINT_FAILURE)?;
+
+    let checkpoint = parse_checkpoint(&checkpoint)?;
+
+    let error = if matches!(checkpoint, Checkpoint::Failed) {
+        resolve_next_line!("error: {}", lines, "Resolve deal error failure").ok()
+    } else {
+        None
+    };
+
+    let updated_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    Ok(DealState {
+        checkpoint,
+        updated_at,
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_known_synonyms_to_their_checkpoint() {
+        for (raw, expected) in [
+            ("Accepted", Checkpoint::Proposed),
+            ("Proposed", Checkpoint::Proposed),
+            ("Transferred", Checkpoint::Transferred),
+            ("Published", Checkpoint::Published),
+            ("PublishConfirmed", Checkpoint::Published),
+            ("Active", Checkpoint::Active),
+            ("Complete", Checkpoint::Active),
+            ("Failed", Checkpoint::Failed),
+            ("Error", Checkpoint::Failed),
+        ] {
+            assert_eq!(parse_checkpoint(raw).unwrap(), expected, "mapping {raw}");
+        }
+    }
+
+    #[test]
+    fn rejects_unrecognized_checkpoints() {
+        assert!(parse_checkpoint("Unknown").is_err());
+    }
+
+    #[test]
+    fn only_failed_and_active_are_terminal() {
+        assert!(!Checkpoint::Proposed.is_terminal());
+        assert!(!Checkpoint::Transferred.is_terminal());
+        assert!(!Checkpoint::Published.is_terminal());
+        assert!(Checkpoint::Active.is_terminal());
+        assert!(Checkpoint::Failed.is_terminal());
+    }
+}